@@ -1,6 +1,6 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use crc::{Crc, CRC_32_ISO_HDLC};
+use clap::{Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use crc::{Crc, CRC_16_IBM_3740, CRC_16_XMODEM, CRC_32_ISO_HDLC};
 use ihex::Reader;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::fs::File;
@@ -8,13 +8,99 @@ use std::io::{Read, Write};
 use std::path::PathBuf;
 use std::time::Duration;
 
+/// Start address of each app bank in an A/B flash layout. Slot A keeps the
+/// historical `0x10010100` offset; slot B sits one 1 MiB bank higher.
+const SLOT_A_BASE: u32 = 0x1001_0100;
+const SLOT_B_BASE: u32 = 0x1011_0100;
+/// Default size of a single app bank, used to locate the metadata trailer.
+const DEFAULT_SLOT_SIZE: u32 = 0x0010_0000;
+
+/// Magic byte that asks the bootloader to start a readback/verify exchange.
+const VERIFY_MAGIC: u8 = 0x56; // 'V'
+
+/// USB vendor ID assigned to Raspberry Pi, shared by the whole RP2 family.
+const RP2_VID: u16 = 0x2E8A;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Slot {
+    A,
+    B,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Protocol {
+    /// The project's custom 0xAA + [len, CRC] header protocol.
+    Custom,
+    /// Plain XMODEM-1K (CRC, 1024-byte packets).
+    Xmodem,
+    /// YMODEM batch transfer (leading block 0 with filename and size).
+    Ymodem,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum VerifyMode {
+    /// Ask the device for its computed CRC32 and compare (4 bytes on the wire).
+    Crc,
+    /// Read the whole flashed region back and compare byte-for-byte.
+    Full,
+}
+
+impl Slot {
+    fn default_base(self) -> u32 {
+        match self {
+            Slot::A => SLOT_A_BASE,
+            Slot::B => SLOT_B_BASE,
+        }
+    }
+}
+
+/// Parse a hex address that may be written with or without a `0x` prefix.
+fn parse_hex_u32(s: &str) -> Result<u32, String> {
+    let trimmed = s.trim_start_matches("0x").trim_start_matches("0X");
+    u32::from_str_radix(trimmed, 16).map_err(|e| format!("invalid hex address '{}': {}", s, e))
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about = "Pico 2 W Rust Downloader")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    flash: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Enumerate serial ports and flag attached Raspberry Pi RP2 boards.
+    List(ListArgs),
+}
+
+#[derive(ClapArgs, Debug)]
+struct ListArgs {
+    #[arg(long, help = "Only show ports with the Raspberry Pi USB vendor ID (RP2 family)")]
+    pico_only: bool,
+}
+
+#[derive(ClapArgs, Debug)]
 struct Args {
-    #[arg(help = "Serial port (e.g. COM3 or /dev/ttyACM0)")]
-    port: String,
+    #[arg(
+        short = 'p',
+        long,
+        required_unless_present = "auto",
+        help = "Serial port (e.g. COM3 or /dev/ttyACM0); omit with --auto"
+    )]
+    port: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "port",
+        help = "Auto-select the only attached RP2 board"
+    )]
+    auto: bool,
 
-    #[arg(help = "Path to .bin or .hex file")]
+    #[arg(required = true, help = "Path to .bin, .hex, or .srec file")]
     file: PathBuf,
 
     #[arg(short, long, default_value_t = 115200, help = "Baud rate")]
@@ -25,10 +111,392 @@ struct Args {
 
     #[arg(short, long, help = "Send 'reboot' command before update")]
     reboot: bool,
+
+    #[arg(
+        long,
+        help = "Reliable mode: frame each chunk with a sequence byte + CRC-16 and wait for an ACK/NAK reply"
+    )]
+    reliable: bool,
+
+    #[arg(
+        long,
+        default_value_t = 5,
+        help = "Max retransmissions per block in reliable mode"
+    )]
+    max_retries: u32,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Slot::A,
+        help = "Target A/B bank for HEX address mapping and the metadata trailer"
+    )]
+    slot: Slot,
+
+    #[arg(
+        long,
+        value_parser = parse_hex_u32,
+        help = "Override the selected slot's start address (hex)"
+    )]
+    base_addr: Option<u32>,
+
+    #[arg(
+        long,
+        value_parser = parse_hex_u32,
+        help = "Override the slot size used to locate the metadata trailer (hex)"
+    )]
+    slot_size: Option<u32>,
+
+    #[arg(
+        long,
+        help = "Append an 8-byte [len, CRC32] trailer at the slot's metadata offset"
+    )]
+    trailer: bool,
+
+    #[arg(
+        long,
+        value_parser = parse_hex_u32,
+        help = "Override the trailer image-length offset (absolute address, default slot_end - 8)"
+    )]
+    size_offset: Option<u32>,
+
+    #[arg(
+        long,
+        value_parser = parse_hex_u32,
+        help = "Override the trailer CRC32 offset (absolute address, default slot_end - 4)"
+    )]
+    crc_offset: Option<u32>,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Protocol::Custom,
+        help = "Transfer protocol for the data stream"
+    )]
+    protocol: Protocol,
+
+    #[arg(long, help = "Read the image back after upload and verify it matches")]
+    verify: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = VerifyMode::Crc,
+        help = "Verification strategy: device CRC comparison or full readback"
+    )]
+    verify_mode: VerifyMode,
+}
+
+/// Print every serial port, annotating USB metadata and flagging RP2 boards.
+fn list_ports(pico_only: bool) -> Result<()> {
+    let ports = serialport::available_ports().context("Enumerating serial ports")?;
+    let mut shown = 0;
+    for p in &ports {
+        match &p.port_type {
+            serialport::SerialPortType::UsbPort(info) => {
+                let is_pico = info.vid == RP2_VID;
+                if pico_only && !is_pico {
+                    continue;
+                }
+                let tag = if is_pico { "  <-- Raspberry Pi RP2" } else { "" };
+                println!("{}{}", p.port_name, tag);
+                println!(
+                    "    USB VID:PID {:04X}:{:04X}",
+                    info.vid, info.pid
+                );
+                println!(
+                    "    Manufacturer: {}",
+                    info.manufacturer.as_deref().unwrap_or("?")
+                );
+                println!("    Product: {}", info.product.as_deref().unwrap_or("?"));
+                println!(
+                    "    Serial: {}",
+                    info.serial_number.as_deref().unwrap_or("?")
+                );
+                shown += 1;
+            }
+            other => {
+                if pico_only {
+                    continue;
+                }
+                println!("{} ({:?})", p.port_name, other);
+                shown += 1;
+            }
+        }
+    }
+    if shown == 0 {
+        println!("No matching serial ports found.");
+    }
+    Ok(())
+}
+
+/// Return the single attached RP2 board's port name, or an error if the count
+/// of matching devices is not exactly one.
+fn find_single_pico() -> Result<String> {
+    let ports = serialport::available_ports().context("Enumerating serial ports")?;
+    let picos: Vec<String> = ports
+        .into_iter()
+        .filter(|p| {
+            matches!(&p.port_type, serialport::SerialPortType::UsbPort(info) if info.vid == RP2_VID)
+        })
+        .map(|p| p.port_name)
+        .collect();
+    match picos.as_slice() {
+        [one] => Ok(one.clone()),
+        [] => anyhow::bail!("--auto: no RP2 board found (try `list`)"),
+        many => anyhow::bail!(
+            "--auto: {} RP2 boards found ({}); pass an explicit --port",
+            many.len(),
+            many.join(", ")
+        ),
+    }
+}
+
+/// Read a single byte, mapping the 5s port timeout to `None` so callers can
+/// poll without treating a quiet line as a hard error.
+fn read_byte(port: &mut dyn serialport::SerialPort) -> Result<Option<u8>> {
+    let mut b = [0u8; 1];
+    match port.read_exact(&mut b) {
+        Ok(()) => Ok(Some(b[0])),
+        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e).context("Reading modem control byte"),
+    }
+}
+
+/// Send `data` to a stock XMODEM-1K/YMODEM receiver, reusing the shared
+/// `ProgressBar` for byte progress. For YMODEM a block 0 carrying the file
+/// name and decimal size leads the transfer and a zero-filled block 0 closes
+/// the batch.
+fn send_modem(
+    port: &mut dyn serialport::SerialPort,
+    data: &[u8],
+    protocol: Protocol,
+    filename: &str,
+    image_len: u32,
+) -> Result<()> {
+    const SOH: u8 = 0x01;
+    const STX: u8 = 0x02;
+    const EOT: u8 = 0x04;
+    const ACK: u8 = 0x06;
+    const NAK: u8 = 0x15;
+    const SUB: u8 = 0x1A; // padding byte
+    const POLL: u8 = 0x43; // 'C', CRC-mode receiver poll
+    const MAX_RETRIES: u32 = 10;
+
+    let crc16 = Crc::<u16>::new(&CRC_16_XMODEM);
+
+    // Send one framed packet and wait for an ACK, retrying on NAK/timeout.
+    let send_packet = |port: &mut dyn serialport::SerialPort,
+                       block: u8,
+                       payload: &[u8]|
+     -> Result<()> {
+        let marker = if payload.len() > 128 { STX } else { SOH };
+        let mut frame = Vec::with_capacity(payload.len() + 5);
+        frame.push(marker);
+        frame.push(block);
+        frame.push(255 - block);
+        frame.extend_from_slice(payload);
+        frame.extend_from_slice(&crc16.checksum(payload).to_be_bytes());
+
+        // One initial send plus up to MAX_RETRIES retransmissions.
+        for _ in 0..=MAX_RETRIES {
+            port.write_all(&frame)?;
+            port.flush()?;
+            match read_byte(port)? {
+                Some(ACK) => return Ok(()),
+                Some(NAK) | None => continue,
+                Some(_) => continue,
+            }
+        }
+        anyhow::bail!("No ACK for block {} after {} retries", block, MAX_RETRIES);
+    };
+
+    // Wait for the receiver's 'C' poll before starting.
+    let wait_for_poll = |port: &mut dyn serialport::SerialPort| -> Result<()> {
+        for _ in 0..MAX_RETRIES {
+            if let Some(POLL) = read_byte(port)? {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("Receiver never sent the 'C' poll");
+    };
+
+    println!("Waiting for receiver poll ('C')...");
+    wait_for_poll(port)?;
+
+    // YMODEM block 0: "<filename>\0<size>\0", padded to 128 bytes with NULs.
+    if protocol == Protocol::Ymodem {
+        let mut payload = vec![0u8; 128];
+        // Advertise the real image length (pre-trailer), not the padded stream.
+        let header = format!("{}\0{}", filename, image_len);
+        let bytes = header.as_bytes();
+        // The block is a fixed 128 bytes; a long filename must not index past it.
+        if bytes.len() > payload.len() {
+            anyhow::bail!(
+                "YMODEM header ({} bytes) exceeds the 128-byte block 0; filename too long",
+                bytes.len()
+            );
+        }
+        payload[..bytes.len()].copy_from_slice(bytes);
+        send_packet(port, 0, &payload)?;
+        // The receiver re-polls with 'C' after accepting the header block.
+        wait_for_poll(port)?;
+    }
+
+    // Data packets, 1024 bytes each, padded with SUB.
+    let pb = ProgressBar::new(data.len() as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+
+    let mut block: u8 = 1;
+    let mut sent = 0;
+    while sent < data.len() {
+        let end = (sent + 1024).min(data.len());
+        let mut payload = [SUB; 1024];
+        payload[..end - sent].copy_from_slice(&data[sent..end]);
+        send_packet(port, block, &payload)?;
+        block = block.wrapping_add(1);
+        sent = end;
+        pb.set_position(sent as u64);
+    }
+    pb.finish_with_message("Upload complete!");
+
+    // End of transmission.
+    for _ in 0..MAX_RETRIES {
+        port.write_all(&[EOT])?;
+        port.flush()?;
+        if let Some(ACK) = read_byte(port)? {
+            break;
+        }
+    }
+
+    // YMODEM: close the batch with a zero-filled block 0.
+    if protocol == Protocol::Ymodem {
+        wait_for_poll(port)?;
+        send_packet(port, 0, &[0u8; 128])?;
+    }
+
+    Ok(())
+}
+
+/// Decode a single Motorola S-record line.
+///
+/// Returns `Some((address, payload))` for data records (S1/S2/S3) and `None`
+/// for the header and count/termination records that carry no flashable
+/// bytes. `lineno` is zero-based and only used to build error messages.
+fn decode_srec_line(lineno: usize, line: &str) -> Result<Option<(u32, Vec<u8>)>> {
+    if !line.starts_with('S') || line.len() < 4 {
+        anyhow::bail!("Malformed S-record at line {}: {:?}", lineno + 1, line);
+    }
+
+    let rtype = line.as_bytes()[1];
+    // Decode the hex payload following the "S<type>" prefix.
+    let payload = &line[2..];
+    if payload.len() % 2 != 0 {
+        anyhow::bail!("Odd-length S-record at line {}", lineno + 1);
+    }
+    let bytes: Vec<u8> = (0..payload.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&payload[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|e| anyhow::anyhow!("Bad hex in S-record line {}: {}", lineno + 1, e))?;
+
+    // bytes[0] is the count of the remaining bytes (address + data + checksum).
+    let count = bytes[0] as usize;
+    if bytes.len() != count + 1 {
+        anyhow::bail!("S-record length mismatch at line {}", lineno + 1);
+    }
+
+    // Checksum is the one's-complement of the LSB of the byte-count,
+    // address and data byte sum.
+    let sum: u32 = bytes[..bytes.len() - 1].iter().map(|&b| b as u32).sum();
+    let expected = !(sum as u8);
+    let checksum = bytes[bytes.len() - 1];
+    if expected != checksum {
+        anyhow::bail!(
+            "S-record checksum error at line {}: expected 0x{:02X}, got 0x{:02X}",
+            lineno + 1,
+            expected,
+            checksum
+        );
+    }
+
+    let addr_size = match rtype {
+        b'1' => 2,
+        b'2' => 3,
+        b'3' => 4,
+        // S0 header plus S5/S7/S8/S9 count and termination records carry
+        // no flashable payload.
+        b'0' | b'5' | b'7' | b'8' | b'9' => return Ok(None),
+        other => anyhow::bail!(
+            "Unsupported S-record type S{} at line {}",
+            other as char,
+            lineno + 1
+        ),
+    };
+
+    // Guard against truncated-but-consistent records: `count` must
+    // still leave room for the full address and the trailing checksum.
+    if bytes.len() < 1 + addr_size + 1 {
+        anyhow::bail!(
+            "S-record too short for S{} address at line {}",
+            rtype as char,
+            lineno + 1
+        );
+    }
+
+    let mut addr = 0u32;
+    for &b in &bytes[1..1 + addr_size] {
+        addr = (addr << 8) | b as u32;
+    }
+    let value = bytes[1 + addr_size..bytes.len() - 1].to_vec();
+    Ok(Some((addr, value)))
+}
+
+/// True when a 4-byte trailer field anchored at `addr` fits inside the
+/// `slot_base..slot_end` region. Uses checked arithmetic so user-supplied
+/// offsets near `u32::MAX` report out-of-range instead of overflowing.
+fn trailer_addr_in_slot(addr: u32, slot_base: u32, slot_end: u32) -> bool {
+    addr >= slot_base && addr.checked_add(4).is_some_and(|end| end <= slot_end)
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+
+    if let Some(Command::List(list_args)) = &cli.command {
+        return list_ports(list_args.pico_only);
+    }
+
+    let args = cli.flash;
+
+    // Resolve the serial port: explicit `--port`, or auto-discovery of a lone board.
+    let port_name = if args.auto {
+        let found = find_single_pico()?;
+        println!("--auto selected {}", found);
+        found
+    } else {
+        args.port.clone().expect("clap guarantees port or --auto")
+    };
+
+    // Resolve the target slot geometry once up front so both the HEX mapper
+    // and the metadata trailer agree on where the app region starts and ends.
+    let slot_base = args.base_addr.unwrap_or_else(|| args.slot.default_base());
+    let slot_size = args.slot_size.unwrap_or(DEFAULT_SLOT_SIZE);
+    let slot_end = slot_base.checked_add(slot_size).ok_or_else(|| {
+        anyhow::anyhow!(
+            "Slot geometry overflows: base 0x{:08X} + size 0x{:08X}",
+            slot_base,
+            slot_size
+        )
+    })?;
+    println!(
+        "Target slot {:?}: base 0x{:08X}, end 0x{:08X}",
+        args.slot, slot_base, slot_end
+    );
 
     // 1. Load data
     let mut data = Vec::new();
@@ -55,9 +523,9 @@ fn main() -> Result<()> {
             match record {
                 ihex::Record::Data { offset, value } => {
                     let target_addr = base_addr + offset as u32;
-                    // Map 0x10010100 -> 0 (relative binary for the app slot)
-                    if target_addr >= 0x10010100 {
-                        let rel_offset = (target_addr - 0x10010100) as usize;
+                    // Map the selected slot's base -> 0 (relative binary for the app slot)
+                    if target_addr >= slot_base {
+                        let rel_offset = (target_addr - slot_base) as usize;
                         if rel_offset + value.len() <= buffer.len() {
                             buffer[rel_offset..rel_offset + value.len()].copy_from_slice(&value);
                             max_offset = max_offset.max(rel_offset + value.len());
@@ -72,7 +540,45 @@ fn main() -> Result<()> {
             }
         }
         if max_offset == 0 {
-            anyhow::bail!("No data found in HEX file for address >= 0x10010100");
+            anyhow::bail!(
+                "No data found in HEX file for address >= 0x{:08X}",
+                slot_base
+            );
+        }
+        data = buffer[..max_offset].to_vec();
+    } else if matches!(extension.as_str(), "srec" | "s19" | "s28" | "s37" | "mot") {
+        println!("Parsing Motorola S-record file: {:?}", args.file);
+        let mut srec_content = String::new();
+        File::open(&args.file)?.read_to_string(&mut srec_content)?;
+
+        // Same sparse-buffer strategy as the Intel HEX path.
+        let mut buffer = vec![0xFFu8; 2 * 1024 * 1024];
+        let mut max_offset = 0;
+
+        for (lineno, line) in srec_content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((addr, value)) = decode_srec_line(lineno, line)? else {
+                continue;
+            };
+
+            // Map the selected slot's base -> 0, matching the HEX path.
+            if addr >= slot_base {
+                let rel_offset = (addr - slot_base) as usize;
+                if rel_offset + value.len() <= buffer.len() {
+                    buffer[rel_offset..rel_offset + value.len()].copy_from_slice(&value);
+                    max_offset = max_offset.max(rel_offset + value.len());
+                }
+            }
+        }
+        if max_offset == 0 {
+            anyhow::bail!(
+                "No data found in S-record file for address >= 0x{:08X}",
+                slot_base
+            );
         }
         data = buffer[..max_offset].to_vec();
     } else {
@@ -91,28 +597,83 @@ fn main() -> Result<()> {
         anyhow::bail!("Empty file or no valid data loaded.");
     }
 
-    // 2. Calculate CRC32
+    // 2. Calculate CRC32 over the firmware image
     let crc_algo = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    let image_crc = crc_algo.checksum(&data);
+    let image_len = data.len() as u32;
+
+    // 2b. Optionally stamp the bootloader metadata trailer at the top of the
+    // slot region: little-endian image length at `size_offset` and CRC32 at
+    // `crc_offset` (default slot_end - 8 / slot_end - 4), padding the gap with
+    // erased-flash 0xFF bytes so the streamed payload spans up to the trailer.
+    if args.trailer {
+        let default_trailer = |back: u32| -> Result<u32> {
+            slot_end.checked_sub(back).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Slot 0x{:08X}..0x{:08X} too small to hold a default trailer",
+                    slot_base,
+                    slot_end
+                )
+            })
+        };
+        let size_addr = match args.size_offset {
+            Some(a) => a,
+            None => default_trailer(8)?,
+        };
+        let crc_addr = match args.crc_offset {
+            Some(a) => a,
+            None => default_trailer(4)?,
+        };
+        for (addr, field) in [(size_addr, b'S'), (crc_addr, b'C')] {
+            if !trailer_addr_in_slot(addr, slot_base, slot_end) {
+                anyhow::bail!(
+                    "Trailer {} offset 0x{:08X} falls outside slot 0x{:08X}..0x{:08X}",
+                    field as char,
+                    addr,
+                    slot_base,
+                    slot_end
+                );
+            }
+        }
+        let size_rel = (size_addr - slot_base) as usize;
+        let crc_rel = (crc_addr - slot_base) as usize;
+        let end_rel = (size_rel + 4).max(crc_rel + 4);
+        if data.len() < end_rel {
+            data.resize(end_rel, 0xFF);
+        }
+        data[size_rel..size_rel + 4].copy_from_slice(&image_len.to_le_bytes());
+        data[crc_rel..crc_rel + 4].copy_from_slice(&image_crc.to_le_bytes());
+        println!(
+            "Stamped trailer: len @ 0x{:08X}, CRC32 @ 0x{:08X}",
+            size_addr, crc_addr
+        );
+    }
+
+    // The header and progress bar describe what is actually streamed, which
+    // includes the trailer region when `--trailer` is set.
     let crc_val = crc_algo.checksum(&data);
     let len = data.len() as u32;
 
-    println!("File loaded. Size: {} bytes, CRC32: 0x{:08X}", len, crc_val);
+    println!(
+        "Image: {} bytes, CRC32 0x{:08X}. Streaming {} bytes, CRC32 0x{:08X}",
+        image_len, image_crc, len, crc_val
+    );
 
     // 3. Open Serial Port with robust settings
-    let mut port = serialport::new(&args.port, args.baud)
+    let mut port = serialport::new(&port_name, args.baud)
         .timeout(Duration::from_millis(5000)) // 5s timeout for Windows stability
         .data_bits(serialport::DataBits::Eight)
         .parity(serialport::Parity::None)
         .stop_bits(serialport::StopBits::One)
         .flow_control(serialport::FlowControl::None)
         .open()
-        .with_context(|| format!("Failed to open port {}", args.port))?;
+        .with_context(|| format!("Failed to open port {}", port_name))?;
 
     // Some USB-serial adapters (and Windows drivers) require DTR/RTS to communicate properly
     port.write_data_terminal_ready(true).ok();
     port.write_request_to_send(true).ok();
 
-    println!("Port {} opened at {} baud.", args.port, args.baud);
+    println!("Port {} opened at {} baud.", port_name, args.baud);
 
     // 4. Remote Reboot if requested
     if args.reboot {
@@ -132,37 +693,206 @@ fn main() -> Result<()> {
     std::thread::sleep(Duration::from_millis(1000));
     port.clear(serialport::ClearBuffer::Input)?;
 
-    // 6. Send Magic Byte
-    println!("Sending Magic 0xAA...");
-    port.write_all(&[0xAA])?;
+    if args.protocol == Protocol::Custom {
+        // 6. Send Magic Byte
+        println!("Sending Magic 0xAA...");
+        port.write_all(&[0xAA])?;
 
-    // 7. Send Header
-    println!("Sending Header: [Len={}, CRC=0x{:08X}]", len, crc_val);
-    let mut header = [0u8; 8];
-    header[0..4].copy_from_slice(&len.to_le_bytes());
-    header[4..8].copy_from_slice(&crc_val.to_le_bytes());
-    port.write_all(&header)?;
+        // 7. Send Header
+        println!("Sending Header: [Len={}, CRC=0x{:08X}]", len, crc_val);
+        let mut header = [0u8; 8];
+        header[0..4].copy_from_slice(&len.to_le_bytes());
+        header[4..8].copy_from_slice(&crc_val.to_le_bytes());
+        port.write_all(&header)?;
 
-    // 8. Stream Data
-    println!("Uploading data...");
-    let pb = ProgressBar::new(len as u64);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
+        // 8. Stream Data
+        println!("Uploading data...");
+        let pb = ProgressBar::new(len as u64);
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .progress_chars("#>-"),
+        );
 
-    let mut sent = 0;
-    while sent < data.len() {
-        let end = (sent + args.chunk_size).min(data.len());
-        port.write_all(&data[sent..end])?;
-        sent = end;
-        pb.set_position(sent as u64);
+        let mut sent = 0;
+        let mut seq: u8 = 0;
+        let crc16_algo = Crc::<u16>::new(&CRC_16_IBM_3740);
+        while sent < data.len() {
+            let end = (sent + args.chunk_size).min(data.len());
+            let block = &data[sent..end];
+
+            if args.reliable {
+                // Frame: [seq] [CRC-16 LE] [payload], retransmitting until the
+                // bootloader replies with ACK or we exhaust the retry budget.
+                let block_crc = crc16_algo.checksum(block);
+                let mut attempt = 0;
+                loop {
+                    port.write_all(&[seq])?;
+                    port.write_all(&block_crc.to_le_bytes())?;
+                    port.write_all(block)?;
+                    port.flush()?;
+
+                    let mut reply = [0u8; 1];
+                    match port.read_exact(&mut reply) {
+                        Ok(()) if reply[0] == 0x06 => break, // ACK
+                        Ok(()) if reply[0] == 0x15 => {}     // NAK, fall through to retry
+                        Ok(()) => {}                         // unexpected byte, treat as NAK
+                        Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {}
+                        Err(e) => return Err(e).context("Reading ACK/NAK reply"),
+                    }
+
+                    attempt += 1;
+                    if attempt > args.max_retries {
+                        anyhow::bail!(
+                            "Transfer aborted after {} attempts on block {} (seq {}, CRC-16 0x{:04X})",
+                            attempt,
+                            sent / args.chunk_size,
+                            seq,
+                            block_crc
+                        );
+                    }
+                }
+                seq = seq.wrapping_add(1);
+            } else {
+                port.write_all(block)?;
+            }
+
+            sent = end;
+            pb.set_position(sent as u64);
+        }
+
+        pb.finish_with_message("Upload complete!");
+    } else {
+        // 6-8. Standard XMODEM-1K / YMODEM transfer for stock bootloaders.
+        let filename = args
+            .file
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or("firmware.bin");
+        send_modem(&mut *port, &data, args.protocol, filename, image_len)?;
+    }
+
+    // 9. Optional readback verification pass.
+    if args.verify {
+        println!("Verifying flashed image ({} bytes)...", len);
+        // Verify request: magic byte followed by the little-endian region length.
+        port.write_all(&[VERIFY_MAGIC])?;
+        port.write_all(&len.to_le_bytes())?;
+        port.flush()?;
+
+        match args.verify_mode {
+            VerifyMode::Crc => {
+                let mut reply = [0u8; 4];
+                port.read_exact(&mut reply)
+                    .context("Reading device-computed CRC32")?;
+                let device_crc = u32::from_le_bytes(reply);
+                if device_crc != crc_val {
+                    anyhow::bail!(
+                        "Verify failed: expected CRC32 0x{:08X}, device reported 0x{:08X}",
+                        crc_val,
+                        device_crc
+                    );
+                }
+                println!("Verify OK: device CRC32 0x{:08X} matches.", device_crc);
+            }
+            VerifyMode::Full => {
+                let vpb = ProgressBar::new(len as u64);
+                vpb.set_style(
+                    ProgressStyle::default_bar()
+                        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.yellow/blue}] {bytes}/{total_bytes} ({eta})")
+                        .unwrap()
+                        .progress_chars("#>-"),
+                );
+
+                let mut readback = vec![0u8; data.len()];
+                let mut read = 0;
+                while read < readback.len() {
+                    let end = (read + args.chunk_size).min(readback.len());
+                    port.read_exact(&mut readback[read..end])
+                        .with_context(|| format!("Reading back bytes {}..{}", read, end))?;
+                    read = end;
+                    vpb.set_position(read as u64);
+                }
+                vpb.finish_and_clear();
+
+                if let Some(offset) = data.iter().zip(&readback).position(|(a, b)| a != b) {
+                    anyhow::bail!(
+                        "Verify failed at offset {}: expected 0x{:02X}, read 0x{:02X}",
+                        offset,
+                        data[offset],
+                        readback[offset]
+                    );
+                }
+                let readback_crc = crc_algo.checksum(&readback);
+                if readback_crc != crc_val {
+                    anyhow::bail!(
+                        "Verify failed: readback CRC32 0x{:08X} != expected 0x{:08X}",
+                        readback_crc,
+                        crc_val
+                    );
+                }
+                println!("Verify OK: full readback matches (CRC32 0x{:08X}).", readback_crc);
+            }
+        }
     }
 
-    pb.finish_with_message("Upload complete!");
     println!("Done.");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_srec_line, trailer_addr_in_slot};
+
+    #[test]
+    fn srec_decodes_valid_s3_record() {
+        // S3, count=06 (4-byte addr + 1 data byte + checksum), addr
+        // 0xAABBCCDD, one data byte 0x11.
+        let sum: u32 = [0x06u8, 0xAA, 0xBB, 0xCC, 0xDD, 0x11]
+            .iter()
+            .map(|&b| b as u32)
+            .sum();
+        let cksum = !(sum as u8);
+        let line = format!("S306AABBCCDD11{:02X}", cksum);
+        let (addr, data) = decode_srec_line(0, &line).unwrap().unwrap();
+        assert_eq!(addr, 0xAABBCCDD);
+        assert_eq!(data, vec![0x11]);
+    }
+
+    #[test]
+    fn srec_skips_header_and_termination_records() {
+        assert!(decode_srec_line(0, "S0030000FC").unwrap().is_none());
+        assert!(decode_srec_line(0, "S9030000FC").unwrap().is_none());
+    }
+
+    #[test]
+    fn srec_rejects_bad_checksum() {
+        // Length-consistent S3 (count=06) but a deliberately wrong checksum.
+        assert!(decode_srec_line(0, "S306AABBCCDD1100").is_err());
+    }
+
+    #[test]
+    fn srec_rejects_truncated_but_consistent_record() {
+        // S3 with count=3 and a valid checksum, but too short to hold a
+        // 4-byte address. Must error, not panic on the address slice.
+        assert!(decode_srec_line(0, "S303AABB97").is_err());
+    }
+
+    #[test]
+    fn trailer_bounds_accept_in_range_offsets() {
+        assert!(trailer_addr_in_slot(0x1000, 0x1000, 0x2000));
+        assert!(trailer_addr_in_slot(0x1FFC, 0x1000, 0x2000));
+    }
+
+    #[test]
+    fn trailer_bounds_reject_out_of_range_without_overflow() {
+        // Below the slot base.
+        assert!(!trailer_addr_in_slot(0x0FFC, 0x1000, 0x2000));
+        // Last 4 bytes spill past slot_end.
+        assert!(!trailer_addr_in_slot(0x1FFD, 0x1000, 0x2000));
+        // u32::MAX must report out-of-range rather than overflow on +4.
+        assert!(!trailer_addr_in_slot(u32::MAX, 0x1000, 0x2000));
+    }
+}